@@ -0,0 +1,374 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+use rand::Rng;
+
+pub type NodeRef = Rc<Node>;
+
+/// A single candidate segment in the lattice: the vocabulary id it names,
+/// the `[pos, pos + length)` span of `Lattice`'s characters it covers, and
+/// the piece's own log-probability `score`. `backtrace_score`/`prev` hold
+/// the best path ending at this node once a forward pass (`viterbi`,
+/// `nbest`) has run over it; `alpha`/`beta` hold the forward/backward
+/// log-sum-exp values once `populate_marginal`/`sample` have run. These are
+/// `Cell`/`RefCell` rather than plain fields so those passes can update them
+/// through a shared `Rc<Node>`.
+#[derive(Debug)]
+pub struct Node {
+    pub id: usize,
+    pub pos: usize,
+    pub length: usize,
+    pub score: f64,
+    pub backtrace_score: Cell<f64>,
+    pub prev: RefCell<Option<NodeRef>>,
+    pub alpha: Cell<f64>,
+    pub beta: Cell<f64>,
+}
+
+impl Node {
+    fn new(id: usize, pos: usize, length: usize, score: f64) -> NodeRef {
+        Rc::new(Self {
+            id,
+            pos,
+            length,
+            score,
+            backtrace_score: Cell::new(0.0),
+            prev: RefCell::new(None),
+            alpha: Cell::new(0.0),
+            beta: Cell::new(0.0),
+        })
+    }
+}
+
+/// Numerically stable `ln(exp(a) + exp(b))`.
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    let (hi, lo) = if a > b { (a, b) } else { (b, a) };
+    hi + (lo - hi).exp().ln_1p()
+}
+
+/// A DAG over every registered vocabulary piece that could start at each
+/// character offset of `sentence`: `begin_nodes[pos]` holds every piece
+/// starting at `pos`, `end_nodes[pos]` holds every piece ending at `pos`.
+/// `Unigram::populate_nodes` fills it in; `viterbi`/`nbest`/`sample`/
+/// `populate_marginal` all walk it left-to-right (and back) to decode,
+/// rank, sample, or train on it.
+pub struct Lattice {
+    chars: Vec<char>,
+    len: usize,
+    bos_id: usize,
+    eos_id: usize,
+    unk_id: usize,
+    begin_nodes: Vec<Vec<NodeRef>>,
+    end_nodes: Vec<Vec<NodeRef>>,
+}
+
+impl Lattice {
+    pub fn from(sentence: &str, bos_id: usize, eos_id: usize, unk_id: usize) -> Self {
+        let chars: Vec<char> = sentence.chars().collect();
+        let len = chars.len();
+        let mut begin_nodes = vec![Vec::new(); len + 1];
+        let mut end_nodes = vec![Vec::new(); len + 1];
+
+        let bos = Node::new(bos_id, 0, 0, 0.0);
+        let eos = Node::new(eos_id, len, 0, 0.0);
+        end_nodes[0].push(bos);
+        begin_nodes[len].push(eos);
+
+        Self {
+            chars,
+            len,
+            bos_id,
+            eos_id,
+            unk_id,
+            begin_nodes,
+            end_nodes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The characters of the sentence this lattice was built over.
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+
+    /// Registers a candidate piece spanning `[pos, pos + length)`.
+    pub fn insert(&mut self, pos: usize, length: usize, score: f64, id: usize) {
+        let node = Node::new(id, pos, length, score);
+        self.end_nodes[pos + length].push(Rc::clone(&node));
+        self.begin_nodes[pos].push(node);
+    }
+
+    fn bos_node(&self) -> NodeRef {
+        Rc::clone(&self.end_nodes[0][0])
+    }
+
+    fn eos_node(&self) -> NodeRef {
+        Rc::clone(&self.begin_nodes[self.len][0])
+    }
+
+    /// Forward Viterbi pass: sets every node's `backtrace_score` to the
+    /// best cumulative score of a path from BOS through it, and `prev` to
+    /// the predecessor that achieves it.
+    fn forward_viterbi(&self) {
+        for pos in 0..=self.len {
+            for rnode in &self.begin_nodes[pos] {
+                let mut best_score = f64::NEG_INFINITY;
+                let mut best_prev = None;
+                for lnode in &self.end_nodes[pos] {
+                    let score = lnode.backtrace_score.get() + rnode.score;
+                    if score > best_score {
+                        best_score = score;
+                        best_prev = Some(Rc::clone(lnode));
+                    }
+                }
+                rnode.backtrace_score.set(best_score);
+                *rnode.prev.borrow_mut() = best_prev;
+            }
+        }
+    }
+
+    /// Forward log-sum-exp pass: sets every node's `alpha` to the
+    /// log-sum-exp of every path from BOS through it.
+    fn forward_alpha(&self) {
+        for pos in 0..=self.len {
+            for rnode in &self.begin_nodes[pos] {
+                let mut lse = f64::NEG_INFINITY;
+                for lnode in &self.end_nodes[pos] {
+                    lse = log_add(lse, lnode.alpha.get() + rnode.score);
+                }
+                rnode.alpha.set(lse);
+            }
+        }
+    }
+
+    /// Backward log-sum-exp pass: sets every node's `beta` to the
+    /// log-sum-exp of every path from it through to EOS.
+    fn backward_beta(&self) {
+        for pos in (0..=self.len).rev() {
+            for lnode in &self.end_nodes[pos] {
+                let mut lse = f64::NEG_INFINITY;
+                for rnode in &self.begin_nodes[pos] {
+                    lse = log_add(lse, rnode.beta.get() + lnode.score);
+                }
+                lnode.beta.set(lse);
+            }
+        }
+    }
+
+    /// The single best (Viterbi) segmentation, as the sequence of nodes
+    /// between BOS and EOS (both excluded).
+    pub fn viterbi(&self) -> Vec<NodeRef> {
+        self.forward_viterbi();
+
+        let bos = self.bos_node();
+        let mut results = Vec::new();
+        let mut node = self.eos_node();
+        loop {
+            let prev = node.prev.borrow().clone();
+            match prev {
+                Some(p) => {
+                    if Rc::ptr_eq(&p, &bos) {
+                        break;
+                    }
+                    results.push(Rc::clone(&p));
+                    node = p;
+                }
+                None => break,
+            }
+        }
+        results.reverse();
+        results
+    }
+
+    /// The `n` best segmentations, most likely first, each as the sequence
+    /// of nodes between BOS and EOS (both excluded). Implemented as an A*
+    /// search over partial paths built backward from EOS: because the
+    /// forward Viterbi `backtrace_score` is the *exact* best achievable
+    /// score for the still-unexplored BOS-ward prefix (not just an
+    /// admissible estimate), popping hypotheses off the priority queue in
+    /// `fx` order yields complete paths in exactly decreasing total score,
+    /// so the first `n` popped are the n-best.
+    pub fn nbest(&self, n: usize) -> Vec<Vec<NodeRef>> {
+        if n == 0 || self.len == 0 {
+            return Vec::new();
+        }
+        self.forward_viterbi();
+
+        struct Hypothesis {
+            node: NodeRef,
+            next: Option<Rc<Hypothesis>>,
+            fx: f64,
+            gx: f64,
+        }
+        struct HeapItem(Rc<Hypothesis>);
+        impl PartialEq for HeapItem {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.fx == other.0.fx
+            }
+        }
+        impl Eq for HeapItem {}
+        impl PartialOrd for HeapItem {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for HeapItem {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.fx.partial_cmp(&other.0.fx).unwrap_or(Ordering::Equal)
+            }
+        }
+
+        let bos = self.bos_node();
+        let eos = self.eos_node();
+
+        let mut heap = BinaryHeap::new();
+        heap.push(HeapItem(Rc::new(Hypothesis {
+            fx: eos.backtrace_score.get(),
+            gx: 0.0,
+            node: Rc::clone(&eos),
+            next: None,
+        })));
+
+        let mut results: Vec<Vec<NodeRef>> = Vec::new();
+        while let Some(HeapItem(hyp)) = heap.pop() {
+            if Rc::ptr_eq(&hyp.node, &bos) {
+                let mut path = Vec::new();
+                let mut cur = hyp.next.clone();
+                while let Some(h) = cur {
+                    if Rc::ptr_eq(&h.node, &eos) {
+                        break;
+                    }
+                    path.push(Rc::clone(&h.node));
+                    cur = h.next.clone();
+                }
+                results.push(path);
+                if results.len() >= n {
+                    break;
+                }
+                continue;
+            }
+
+            let pos = hyp.node.pos;
+            let node_score = hyp.node.score;
+            for lnode in &self.end_nodes[pos] {
+                let gx = hyp.gx + node_score;
+                let fx = gx + lnode.backtrace_score.get();
+                heap.push(HeapItem(Rc::new(Hypothesis {
+                    fx,
+                    gx,
+                    node: Rc::clone(lnode),
+                    next: Some(Rc::clone(&hyp)),
+                })));
+            }
+        }
+        results
+    }
+
+    /// Samples a segmentation (SentencePiece-style subword regularization):
+    /// a forward log-sum-exp pass computes, per node, the total score of
+    /// every path from BOS through it, then a backward pass samples a
+    /// predecessor at each step with probability proportional to
+    /// `exp(theta * alpha)`, its (temperature-scaled) share of that mass.
+    /// At `theta == 1.0` a segmentation is drawn with probability
+    /// proportional to `exp(path_score)`; the forward pass is not rescaled
+    /// by `theta`, so for other values the path probabilities are not
+    /// exactly proportional to `exp(theta * path_score)`. `theta <= 0.0`
+    /// degenerates to the deterministic Viterbi segmentation.
+    pub fn sample(&self, theta: f64) -> Vec<NodeRef> {
+        if theta <= 0.0 || self.len == 0 {
+            return self.viterbi();
+        }
+        self.forward_alpha();
+
+        let bos = self.bos_node();
+        let mut rng = rand::thread_rng();
+        let mut results = Vec::new();
+        let mut node = self.eos_node();
+        loop {
+            let pos = node.pos;
+            let candidates = &self.end_nodes[pos];
+            if candidates.len() == 1 && Rc::ptr_eq(&candidates[0], &bos) {
+                break;
+            }
+
+            // `alpha` already includes the candidate's own score. Shift by the
+            // largest exponent so long sentences don't underflow to zero.
+            let max_logit = candidates
+                .iter()
+                .map(|c| theta * c.alpha.get())
+                .fold(f64::NEG_INFINITY, f64::max);
+            let weights: Vec<f64> = candidates
+                .iter()
+                .map(|c| (theta * c.alpha.get() - max_logit).exp())
+                .collect();
+            let total: f64 = weights.iter().sum();
+            let mut r = rng.gen::<f64>() * total;
+            let mut chosen = Rc::clone(&candidates[0]);
+            for (candidate, weight) in candidates.iter().zip(weights.iter()) {
+                if r < *weight {
+                    chosen = Rc::clone(candidate);
+                    break;
+                }
+                r -= weight;
+            }
+
+            if Rc::ptr_eq(&chosen, &bos) {
+                break;
+            }
+            results.push(Rc::clone(&chosen));
+            node = chosen;
+        }
+        results.reverse();
+        results
+    }
+
+    /// Runs the forward-backward algorithm and accumulates, for every node
+    /// crossed by any segmentation, its `freq`-weighted posterior
+    /// probability into `expected[node.id]`. Returns `freq * log Z`, the
+    /// frequency-weighted log-likelihood of the sentence under the current
+    /// model, for use in the training objective.
+    pub fn populate_marginal(&self, freq: f64, expected: &mut [f64]) -> f64 {
+        if self.len == 0 {
+            return 0.0;
+        }
+        self.forward_alpha();
+        self.backward_beta();
+
+        let z = self.eos_node().alpha.get();
+        for pos in 0..self.len {
+            for node in &self.begin_nodes[pos] {
+                // `alpha` and `beta` both include the node's own score.
+                let posterior = (node.alpha.get() + node.beta.get() - node.score - z).exp();
+                expected[node.id] += freq * posterior;
+            }
+        }
+        freq * z
+    }
+
+    pub fn bos_id(&self) -> usize {
+        self.bos_id
+    }
+
+    pub fn eos_id(&self) -> usize {
+        self.eos_id
+    }
+
+    pub fn unk_id(&self) -> usize {
+        self.unk_id
+    }
+}
@@ -0,0 +1,7 @@
+pub mod lattice;
+pub mod model;
+pub mod trainer;
+
+pub use lattice::Lattice;
+pub use model::Unigram;
+pub use trainer::{UnigramTrainer, UnigramTrainerBuilder};
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use super::lattice::Lattice;
+
+/// A trained Unigram language model over sentencepieces: a scored
+/// vocabulary plus the bos/eos/unk ids reserved within it. `populate_nodes`
+/// expands a `Lattice` against this vocabulary; `tokenize` decodes a
+/// sentence through that lattice, either deterministically (Viterbi) or,
+/// once sampling is enabled, stochastically for subword regularization.
+pub struct Unigram {
+    vocab: Vec<(String, f64)>,
+    token_to_ids: HashMap<String, u32>,
+    pub min_score: f64,
+    bos_id: usize,
+    eos_id: usize,
+    unk_id: usize,
+    sample: bool,
+    theta: f64,
+}
+
+impl Unigram {
+    pub fn from(pieces: &[(String, f64)], bos_id: usize, eos_id: usize, unk_id: usize) -> Self {
+        let mut token_to_ids = HashMap::new();
+        for (id, (token, _score)) in pieces.iter().enumerate() {
+            token_to_ids.insert(token.clone(), id as u32);
+        }
+        let min_score = pieces
+            .iter()
+            .map(|(_, score)| *score)
+            .fold(f64::INFINITY, f64::min);
+
+        Self {
+            vocab: pieces.to_vec(),
+            token_to_ids,
+            min_score,
+            bos_id,
+            eos_id,
+            unk_id,
+            sample: false,
+            theta: 1.0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.vocab.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vocab.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.vocab.iter().map(|(token, score)| (token, *score))
+    }
+
+    /// Enables (or disables) subword-regularization sampling for
+    /// `tokenize`, at inverse temperature `theta`. `theta <= 0.0` behaves
+    /// like sampling being disabled: `tokenize` falls back to Viterbi.
+    pub fn set_sample_parameters(&mut self, sample: bool, theta: f64) {
+        self.sample = sample;
+        self.theta = theta;
+    }
+
+    /// Populates `lattice` with every vocabulary piece that matches a
+    /// substring of its sentence, falling back to `<unk>` for any
+    /// character no registered piece covers on its own.
+    pub fn populate_nodes(&self, lattice: &mut Lattice) {
+        let chars = lattice.chars().to_vec();
+        let len = chars.len();
+
+        for pos in 0..len {
+            let mut has_single_char = false;
+            let mut piece = String::new();
+            for end in (pos + 1)..=len {
+                piece.push(chars[end - 1]);
+                if let Some(&id) = self.token_to_ids.get(&piece) {
+                    let score = self.vocab[id as usize].1;
+                    lattice.insert(pos, end - pos, score, id as usize);
+                    if end == pos + 1 {
+                        has_single_char = true;
+                    }
+                }
+            }
+            if !has_single_char {
+                lattice.insert(pos, 1, self.min_score - 10.0, self.unk_id);
+            }
+        }
+    }
+
+    /// Segments `sentence` into the pieces of its current best (or, with
+    /// sampling enabled, a sampled) segmentation.
+    pub fn tokenize(&self, sentence: &str) -> Vec<String> {
+        let mut lattice = Lattice::from(sentence, self.bos_id, self.eos_id, self.unk_id);
+        self.populate_nodes(&mut lattice);
+
+        let nodes = if self.sample {
+            lattice.sample(self.theta)
+        } else {
+            lattice.viterbi()
+        };
+
+        nodes
+            .iter()
+            .map(|node| self.vocab[node.id].0.clone())
+            .collect()
+    }
+}
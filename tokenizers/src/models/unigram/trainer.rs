@@ -1,11 +1,11 @@
 use crate::models::unigram::{lattice::Lattice, model::Unigram};
 use crate::tokenizer::{AddedToken, Model, Result, Trainer};
 use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 
 type SentencePiece = (String, f64);
-const SEED_SIZE: usize = 1_000_000;
 
 fn digamma(x: f64) -> f64 {
     let mut x = x;
@@ -31,14 +31,78 @@ fn to_log_prob(pieces: &mut [SentencePiece]) {
     }
 }
 
+/// Builds the thread pool `run_e_step` parallelizes over, once, from the
+/// configured thread count. `n_threads <= 1` keeps training single-threaded
+/// and deterministic, so no pool is needed.
+fn build_thread_pool(n_threads: usize) -> Option<rayon::ThreadPool> {
+    if n_threads > 1 {
+        Some(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(n_threads)
+                .build()
+                .expect("failed to build the Unigram trainer's thread pool"),
+        )
+    } else {
+        None
+    }
+}
+
+/// Counts how often each character occurs across `word_counts`' keys; each
+/// key is counted once, whatever its count in `word_counts`.
+fn count_chars(word_counts: &HashMap<String, u32>) -> HashMap<char, u32> {
+    let mut all_chars: HashMap<char, u32> = HashMap::new();
+    for string in word_counts.keys() {
+        for c in string.chars() {
+            *all_chars.entry(c).or_insert(0) += 1;
+        }
+    }
+    all_chars
+}
+
 pub struct UnigramTrainerBuilder {
     show_progress: bool,
+    vocab_size: u32,
+    n_sub_iterations: u32,
+    n_iterations: u32,
+    max_piece_length: usize,
+    split_by_whitespace: bool,
+    split_by_number: bool,
+    seed_size: usize,
+    shrinking_factor: f64,
+    min_frequency: u32,
+    unk_token: String,
+    bos_token: String,
+    eos_token: String,
+    special_tokens: Vec<AddedToken>,
+    n_threads: usize,
+    character_coverage: f64,
+    byte_fallback: bool,
+    training_mode: TrainingMode,
+    n_best_size: usize,
 }
 
 impl Default for UnigramTrainerBuilder {
     fn default() -> Self {
         UnigramTrainerBuilder {
             show_progress: true,
+            vocab_size: 8_000,
+            n_sub_iterations: 2,
+            n_iterations: 20,
+            max_piece_length: 16,
+            split_by_whitespace: true,
+            split_by_number: true,
+            seed_size: 1_000_000,
+            shrinking_factor: 0.75,
+            min_frequency: 0,
+            unk_token: "<unk>".to_string(),
+            bos_token: "<bos>".to_string(),
+            eos_token: "<eos>".to_string(),
+            special_tokens: vec![],
+            n_threads: 1,
+            character_coverage: 0.9995,
+            byte_fallback: false,
+            training_mode: TrainingMode::EM,
+            n_best_size: 2,
         }
     }
 }
@@ -49,8 +113,149 @@ impl UnigramTrainerBuilder {
         self
     }
 
+    /// Target vocabulary size once training is finalized.
+    pub fn vocab_size(mut self, vocab_size: u32) -> Self {
+        self.vocab_size = vocab_size;
+        self
+    }
+
+    /// Number of EM sub-iterations run between each pruning pass.
+    pub fn n_sub_iterations(mut self, n_sub_iterations: u32) -> Self {
+        self.n_sub_iterations = n_sub_iterations;
+        self
+    }
+
+    /// Maximum number of EM+prune outer iterations.
+    pub fn n_iterations(mut self, n_iterations: u32) -> Self {
+        self.n_iterations = n_iterations;
+        self
+    }
+
+    /// Maximum number of characters a candidate sentencepiece may contain.
+    pub fn max_piece_length(mut self, max_piece_length: usize) -> Self {
+        self.max_piece_length = max_piece_length;
+        self
+    }
+
+    /// If set, a candidate sentencepiece may only contain whitespace as its
+    /// very first character, never in the middle.
+    pub fn split_by_whitespace(mut self, split_by_whitespace: bool) -> Self {
+        self.split_by_whitespace = split_by_whitespace;
+        self
+    }
+
+    /// If set, a candidate sentencepiece may not mix digits with letters.
+    pub fn split_by_number(mut self, split_by_number: bool) -> Self {
+        self.split_by_number = split_by_number;
+        self
+    }
+
+    /// Maximum number of seed sentencepieces kept before the EM loop starts.
+    pub fn seed_size(mut self, seed_size: usize) -> Self {
+        self.seed_size = seed_size;
+        self
+    }
+
+    /// Fraction of the current vocabulary kept at each pruning pass.
+    pub fn shrinking_factor(mut self, shrinking_factor: f64) -> Self {
+        self.shrinking_factor = shrinking_factor;
+        self
+    }
+
+    /// Minimum corpus frequency a candidate substring must reach to be
+    /// considered as a seed sentencepiece.
+    pub fn min_frequency(mut self, min_frequency: u32) -> Self {
+        self.min_frequency = min_frequency;
+        self
+    }
+
+    /// String used for the unknown token.
+    pub fn unk_token(mut self, unk_token: String) -> Self {
+        self.unk_token = unk_token;
+        self
+    }
+
+    /// String used for the beginning-of-sentence token.
+    pub fn bos_token(mut self, bos_token: String) -> Self {
+        self.bos_token = bos_token;
+        self
+    }
+
+    /// String used for the end-of-sentence token.
+    pub fn eos_token(mut self, eos_token: String) -> Self {
+        self.eos_token = eos_token;
+        self
+    }
+
+    /// Additional tokens to add to the resulting vocabulary.
+    pub fn special_tokens(mut self, special_tokens: Vec<AddedToken>) -> Self {
+        self.special_tokens = special_tokens;
+        self
+    }
+
+    /// Number of threads used to parallelize the E step over sentences.
+    /// Defaults to `1`, which keeps training single-threaded and
+    /// deterministic.
+    pub fn n_threads(mut self, n_threads: usize) -> Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Fraction of the corpus's character occurrences that the vocabulary's
+    /// required characters must cover. Characters outside this coverage
+    /// budget are left out of the required set.
+    pub fn character_coverage(mut self, character_coverage: f64) -> Self {
+        self.character_coverage = character_coverage;
+        self
+    }
+
+    /// If set, adds the 256 byte-value pieces `<0x00>`..`<0xFF>` to the
+    /// final vocabulary. `Unigram` does not use them when encoding: a
+    /// character with no piece of its own is still encoded as the unknown
+    /// token.
+    pub fn byte_fallback(mut self, byte_fallback: bool) -> Self {
+        self.byte_fallback = byte_fallback;
+        self
+    }
+
+    /// Selects the M-step objective: plain EM (the default) or a
+    /// Pitman-Yor process prior.
+    pub fn training_mode(mut self, training_mode: TrainingMode) -> Self {
+        self.training_mode = training_mode;
+        self
+    }
+
+    /// Number of segmentations of a piece requested from the lattice's
+    /// n-best search while pruning. Only the best two are used, so a value
+    /// below 2 keeps every piece.
+    pub fn n_best_size(mut self, n_best_size: usize) -> Self {
+        self.n_best_size = n_best_size;
+        self
+    }
+
     pub fn build(&self) -> UnigramTrainer {
-        UnigramTrainer::new(self.show_progress)
+        UnigramTrainer {
+            show_progress: self.show_progress,
+            vocab_size: self.vocab_size,
+            n_sub_iterations: self.n_sub_iterations,
+            n_iterations: self.n_iterations,
+            special_tokens: self.special_tokens.clone(),
+            n_best_size: self.n_best_size,
+            max_piece_length: self.max_piece_length,
+            split_by_whitespace: self.split_by_whitespace,
+            split_by_number: self.split_by_number,
+            seed_size: self.seed_size,
+            shrinking_factor: self.shrinking_factor,
+            min_frequency: self.min_frequency,
+            unk_token: self.unk_token.clone(),
+            bos_token: self.bos_token.clone(),
+            eos_token: self.eos_token.clone(),
+            n_threads: self.n_threads,
+            thread_pool: build_thread_pool(self.n_threads),
+            character_coverage: self.character_coverage,
+            byte_fallback: self.byte_fallback,
+            training_mode: self.training_mode,
+        }
     }
 }
 
@@ -58,7 +263,49 @@ pub struct UnigramTrainer {
     show_progress: bool,
     vocab_size: u32,
     n_sub_iterations: u32,
+    /// Maximum number of EM+prune outer iterations. Each pass keeps only
+    /// `shrinking_factor` of the previous vocabulary, so this must be large
+    /// enough for the seed table to actually shrink down to `vocab_size`
+    /// (roughly `log(vocab_size / seed_size) / log(shrinking_factor)` passes,
+    /// ~17 at the defaults); too low and the loop exits early with whatever
+    /// oversized, unconverged vocabulary it still has.
+    n_iterations: u32,
     special_tokens: Vec<AddedToken>,
+    /// Number of segmentations of a piece requested from `Lattice::nbest`
+    /// during pruning; only the best two are used.
+    n_best_size: usize,
+    /// Maximum number of characters a candidate sentencepiece may contain.
+    max_piece_length: usize,
+    /// Whether whitespace may only appear as the first character of a piece.
+    split_by_whitespace: bool,
+    /// Whether digits and letters are forbidden from sharing a piece.
+    split_by_number: bool,
+    /// Maximum number of seed sentencepieces kept before the EM loop starts.
+    seed_size: usize,
+    /// Fraction of the current vocabulary kept at each pruning pass.
+    shrinking_factor: f64,
+    /// Minimum corpus frequency a candidate substring must reach to be
+    /// considered as a seed sentencepiece.
+    min_frequency: u32,
+    unk_token: String,
+    bos_token: String,
+    eos_token: String,
+    /// Number of threads used to parallelize the E step over sentences.
+    /// `1` keeps training single-threaded and deterministic.
+    n_threads: usize,
+    /// Thread pool sized to `n_threads`, built once so `run_e_step` doesn't
+    /// pay rayon thread spin-up/teardown cost on every call. `None` when
+    /// `n_threads <= 1`, in which case the E step just runs sequentially.
+    thread_pool: Option<rayon::ThreadPool>,
+    /// Fraction of the corpus's character occurrences that the required
+    /// characters must cover; rarer characters are not forced into the
+    /// final vocabulary.
+    character_coverage: f64,
+    /// Whether to add the 256 byte-value pieces to the final vocabulary.
+    /// `Unigram` does not use them when encoding.
+    byte_fallback: bool,
+    /// The M-step objective: plain EM or a Pitman-Yor process prior.
+    training_mode: TrainingMode,
 }
 
 impl Default for UnigramTrainer {
@@ -67,33 +314,76 @@ impl Default for UnigramTrainer {
             show_progress: true,
             vocab_size: 8_000,
             n_sub_iterations: 2,
+            n_iterations: 20,
             special_tokens: vec![],
+            n_best_size: 2,
+            max_piece_length: 16,
+            split_by_whitespace: true,
+            split_by_number: true,
+            seed_size: 1_000_000,
+            shrinking_factor: 0.75,
+            min_frequency: 0,
+            unk_token: "<unk>".to_string(),
+            bos_token: "<bos>".to_string(),
+            eos_token: "<eos>".to_string(),
+            n_threads: 1,
+            thread_pool: build_thread_pool(1),
+            character_coverage: 0.9995,
+            byte_fallback: false,
+            training_mode: TrainingMode::EM,
         }
     }
 }
 
-static MAX_PIECE_LENGTH: usize = 16;
-
-fn is_valid_sentencepiece(char_string: &[char]) -> bool {
-    // TODO
-    // Checks string length, space not in the substring, numbers, hiragana and more
-    // https://github.com/google/sentencepiece/blob/26be9516cd81d5315ee31c48d2438018e0eab879/src/trainer_interface.cc#L203
-    let n = char_string.len();
-    if char_string.is_empty() || n > MAX_PIECE_LENGTH {
-        // println!("Too long");
-        return false;
-    }
-    true
-    // for (i, c) in char_string.iter().enumerate() {
-    //     if *c == ' ' && i > 0 {
-    //         // println!("Invalid prefix");
-    //         return false;
-    //     }
-    // }
-    // // This function checks that unicode "scripts" are consistent, so we cannot have romaji and
-    // // hiragana for instance. Seems pretty specific. Also Hiragana and katakana are mixed
+/// Selects the objective used by `run_m_step` to turn expected counts into
+/// piece scores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrainingMode {
+    /// The digamma-based Bayesianified/DPified EM sparse prior.
+    EM,
+    /// A Pitman-Yor process prior over the piece multinomial, which tends to
+    /// give better-calibrated probabilities to rare, long-tail pieces.
+    PitmanYor { discount: f64, concentration: f64 },
+}
 
-    // true
+impl Default for TrainingMode {
+    fn default() -> Self {
+        TrainingMode::EM
+    }
+}
+
+/// Unicode script groups that `is_valid_sentencepiece` keeps from mixing
+/// within a single piece. Hiragana, Katakana and Han are treated as one
+/// compatible group (as in Japanese text they routinely sit next to each
+/// other inside a single word), while punctuation and other symbols are
+/// script-neutral and never trigger a mismatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Hangul,
+    CjkIdeographic,
+}
+
+fn get_script(c: char) -> Option<Script> {
+    match c {
+        '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+        | '\u{F900}'..='\u{FAFF}' => Some(Script::CjkIdeographic), // CJK compatibility
+        '\u{0400}'..='\u{04FF}' => Some(Script::Cyrillic),
+        '\u{0370}'..='\u{03FF}' => Some(Script::Greek),
+        '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Some(Script::Arabic),
+        '\u{0590}'..='\u{05FF}' => Some(Script::Hebrew),
+        '\u{0900}'..='\u{097F}' => Some(Script::Devanagari),
+        '\u{AC00}'..='\u{D7A3}' | '\u{1100}'..='\u{11FF}' => Some(Script::Hangul),
+        c if c.is_alphabetic() => Some(Script::Latin),
+        _ => None,
+    }
 }
 
 impl UnigramTrainer {
@@ -102,8 +392,69 @@ impl UnigramTrainer {
             show_progress,
             vocab_size: 8_000,
             n_sub_iterations: 2,
+            n_iterations: 20,
             special_tokens: vec![],
+            n_best_size: 2,
+            max_piece_length: 16,
+            split_by_whitespace: true,
+            split_by_number: true,
+            seed_size: 1_000_000,
+            shrinking_factor: 0.75,
+            min_frequency: 0,
+            unk_token: "<unk>".to_string(),
+            bos_token: "<bos>".to_string(),
+            eos_token: "<eos>".to_string(),
+            n_threads: 1,
+            thread_pool: build_thread_pool(1),
+            character_coverage: 0.9995,
+            byte_fallback: false,
+            training_mode: TrainingMode::EM,
+        }
+    }
+
+    /// Checks whether `char_string` is a usable sentencepiece: short enough,
+    /// free of the sentence boundary marker, and—depending on the trainer's
+    /// configuration—free of internal whitespace, mixed Unicode scripts, and
+    /// digit/letter mixing.
+    fn is_valid_sentencepiece(&self, char_string: &[char]) -> bool {
+        let n = char_string.len();
+        if char_string.is_empty() || n > self.max_piece_length {
+            return false;
+        }
+
+        let mut has_letter = false;
+        let mut has_digit = false;
+        let mut script: Option<Script> = None;
+
+        for (i, c) in char_string.iter().enumerate() {
+            if *c == '\0' {
+                return false;
+            }
+            if c.is_whitespace() {
+                if self.split_by_whitespace && i > 0 {
+                    return false;
+                }
+                continue;
+            }
+            if c.is_numeric() {
+                has_digit = true;
+                continue;
+            }
+            if let Some(s) = get_script(*c) {
+                has_letter = true;
+                match script {
+                    None => script = Some(s),
+                    Some(prev) if prev != s => return false,
+                    _ => {}
+                }
+            }
         }
+
+        if self.split_by_number && has_digit && has_letter {
+            return false;
+        }
+
+        true
     }
 
     /// Setup a progress bar if asked to show progress
@@ -130,9 +481,9 @@ impl UnigramTrainer {
         let mut pieces: HashMap<String, f64> = HashMap::new();
         let existing_pieces: HashMap<&String, f64> = model.iter().collect();
         // XXX: Make sure bos, eos and unk exists and are ids 0, 1, 2
-        pieces.insert("<bos>".to_string(), 0.0);
-        pieces.insert("<eos>".to_string(), 0.0);
-        pieces.insert("<unk>".to_string(), 0.0);
+        pieces.insert(self.bos_token.clone(), 0.0);
+        pieces.insert(self.eos_token.clone(), 0.0);
+        pieces.insert(self.unk_token.clone(), 0.0);
         for c in required_chars {
             if let Some(t) = existing_pieces.get(&c) {
                 pieces.insert(c, *t);
@@ -143,28 +494,60 @@ impl UnigramTrainer {
                 min_score_penalty += min_score_penalty_delta;
             }
         }
+        // Byte-value pieces `<0x00>`..`<0xFF>`. Each byte gets its own score
+        // just above `model.min_score`, continuing the required-chars
+        // increments above in byte order, so the byte pieces' order among
+        // themselves does not depend on the `HashMap`'s iteration order.
+        // `Unigram::populate_nodes` never matches them against text.
+        if self.byte_fallback {
+            for byte in 0..=255u16 {
+                let piece = format!("<0x{:02X}>", byte);
+                pieces.entry(piece).or_insert_with(|| {
+                    let score = model.min_score + min_score_penalty;
+                    min_score_penalty += min_score_penalty_delta;
+                    score
+                });
+            }
+        }
         for (token, score) in model.iter() {
+            if pieces.len() >= self.vocab_size as usize {
+                break;
+            }
             match pieces.get(token) {
                 Some(_) => continue,
                 None => pieces.insert(token.to_string(), score),
             };
-            if pieces.len() == self.vocab_size as usize {
-                break;
-            }
         }
         let mut final_pieces: Vec<SentencePiece> = pieces.into_iter().collect();
         final_pieces.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
         Unigram::from(&final_pieces, 0, 1, 2)
     }
 
-    fn required_chars(&self, word_counts: &HashMap<String, u32>) -> HashSet<String> {
-        // TODO more logic needed if this required chars > vocab_size
-        word_counts
-            .iter()
-            .map(|(s, _count)| s.chars())
-            .flatten()
-            .map(|c| c.to_string())
-            .collect()
+    /// Picks the characters that must be present in the final vocabulary so
+    /// that `character_coverage` of the corpus's character occurrences are
+    /// covered, sorted by descending corpus frequency. The remaining, rarer
+    /// characters are left out instead of forcing the vocabulary budget to
+    /// absorb every distinct character in the corpus; one of them stays in
+    /// the vocabulary only if the trained model keeps it as an ordinary
+    /// piece, and is otherwise encoded as `<unk>` (byte-fallback pieces are
+    /// not used for it).
+    fn required_chars(&self, all_chars: &HashMap<char, u32>) -> HashSet<String> {
+        let total: u32 = all_chars.values().sum();
+        let target = (total as f64 * self.character_coverage).ceil() as u32;
+
+        let mut by_freq: Vec<(u32, char)> = all_chars.iter().map(|(&c, &freq)| (freq, c)).collect();
+        by_freq.sort_by(|a, b| b.cmp(a));
+
+        let mut cumulative = 0;
+        let mut kept = HashSet::new();
+        for (freq, c) in by_freq {
+            if cumulative >= target {
+                break;
+            }
+            cumulative += freq;
+            kept.insert(c.to_string());
+        }
+        kept
     }
     fn make_seed_sentence_pieces(
         &self,
@@ -179,7 +562,6 @@ impl UnigramTrainer {
             .sum::<usize>()
             + word_counts.len();
         let mut flat_string = String::with_capacity(total);
-        let mut all_chars: HashMap<char, u32> = HashMap::new();
         let c_sentence_boundary = '\0';
         let k_sentence_boundary = '\0'.to_string();
         for string in word_counts.keys() {
@@ -188,12 +570,8 @@ impl UnigramTrainer {
             // Comment suggests we add sentence boupiece, but it seems to be missing from actual
             // code.
             flat_string.push_str(&k_sentence_boundary);
-            for c in string.chars() {
-                if c != c_sentence_boundary {
-                    *all_chars.entry(c).or_insert(0) += 1;
-                }
-            }
         }
+        let all_chars = count_chars(word_counts);
         let suffix = esaxx_rs::suffix(&flat_string).unwrap();
 
         self.update_progress(&progress, vocab_size, "Updating frequent sub strings...");
@@ -212,7 +590,10 @@ impl UnigramTrainer {
                 if string.contains(&c_sentence_boundary) {
                     return None;
                 }
-                if !is_valid_sentencepiece(string) {
+                if !self.is_valid_sentencepiece(string) {
+                    return None;
+                }
+                if freq < self.min_frequency {
                     return None;
                 }
                 let score = freq * string.len() as u32;
@@ -236,10 +617,10 @@ impl UnigramTrainer {
         substr_index.sort_by(|a, b| b.cmp(a));
         for (score, char_string) in substr_index {
             // Just in case
-            assert!(is_valid_sentencepiece(char_string));
+            assert!(self.is_valid_sentencepiece(char_string));
             let string: String = char_string.iter().collect();
             seed_sentencepieces.push((string, score.into()));
-            if seed_sentencepieces.len() >= SEED_SIZE {
+            if seed_sentencepieces.len() >= self.seed_size {
                 break;
             }
 
@@ -251,8 +632,90 @@ impl UnigramTrainer {
         self.finalize_progress(&progress, vocab_size);
         Ok(seed_sentencepieces)
     }
-    fn prune_sentence_pieces(&self) {
-        // TODO
+    /// Prunes the vocabulary towards `desired_vocab_size` using the
+    /// likelihood-loss criterion from the SentencePiece Unigram trainer.
+    ///
+    /// For every piece we estimate, via n-best Viterbi, how it would be
+    /// resegmented using only the *other* pieces in the vocabulary. Pieces
+    /// whose own string already Viterbi-segments to themselves (or that have
+    /// no alternative segmentation) are kept unconditionally, along with
+    /// single-character pieces. The rest are ranked by the log-likelihood
+    /// loss incurred by dropping them and redistributing their corpus mass
+    /// onto their best alternative, and only the least costly ones to drop
+    /// are pruned.
+    fn prune_sentence_pieces(
+        &self,
+        model: &Unigram,
+        pieces: &[SentencePiece],
+        sentences: &[(String, u32)],
+        desired_vocab_size: usize,
+    ) -> Vec<SentencePiece> {
+        let mut always_keep = vec![true; pieces.len()];
+        let mut alternatives: Vec<Vec<usize>> = vec![Vec::new(); pieces.len()];
+
+        // For every piece, find out how it would be resegmented without
+        // itself: run n-best (k = `n_best_size`) Viterbi on the piece's own
+        // string. If the best path already recovers the piece whole, there
+        // is no useful alternative and the piece is always kept.
+        for (id, (token, _score)) in pieces.iter().enumerate() {
+            let mut lattice = Lattice::from(token, 0, 1, 2);
+            model.populate_nodes(&mut lattice);
+
+            let nbests = lattice.nbest(self.n_best_size);
+            if nbests.len() < 2 || nbests[0].len() == 1 {
+                always_keep[id] = true;
+            } else {
+                always_keep[id] = false;
+                alternatives[id] = nbests[1].iter().map(|node| node.id).collect();
+            }
+        }
+
+        // Viterbi-segment the whole corpus once to get each piece's corpus
+        // frequency on the current best segmentation.
+        let mut freq: Vec<f64> = vec![0.0; pieces.len()];
+        for (sentence, count) in sentences {
+            let mut lattice = Lattice::from(sentence, 0, 1, 2);
+            model.populate_nodes(&mut lattice);
+            for node in lattice.viterbi() {
+                freq[node.id] += *count as f64;
+            }
+        }
+        let vsum: f64 = freq.iter().sum();
+
+        // Rank removable pieces by the log-likelihood loss incurred by
+        // dropping them and redistributing their mass onto their
+        // alternative segmentation.
+        let mut candidates: Vec<(usize, f64)> = Vec::new();
+        let mut kept: Vec<SentencePiece> = Vec::with_capacity(pieces.len());
+        for (id, (token, score)) in pieces.iter().enumerate() {
+            let required = token.chars().count() == 1;
+            if required || always_keep[id] {
+                kept.push((token.clone(), *score));
+                continue;
+            }
+            let alt = &alternatives[id];
+            if alt.is_empty() || freq[id] == 0.0 {
+                // Nothing segments through this piece on the current
+                // lattice; it is free to drop without touching the
+                // objective, so it is the first candidate for pruning.
+                candidates.push((id, f64::NEG_INFINITY));
+                continue;
+            }
+            let vsum_alt = vsum + freq[id] * (alt.len() as f64 - 1.0);
+            let alt_loglik: f64 = alt.iter().map(|&a| (freq[a] / vsum_alt).ln()).sum();
+            let loss = freq[id] * ((freq[id] / vsum).ln() - alt_loglik);
+            candidates.push((id, loss));
+        }
+        candidates.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+
+        let target =
+            desired_vocab_size.max((pieces.len() as f64 * self.shrinking_factor) as usize);
+        let budget = target.saturating_sub(kept.len());
+        for (id, _loss) in candidates.into_iter().take(budget) {
+            kept.push(pieces[id].clone());
+        }
+
+        kept
     }
 
     /// Update the progress bar with the new provided length and message
@@ -273,41 +736,48 @@ impl UnigramTrainer {
         }
     }
 
-    fn run_e_step(&self, model: &mut Unigram, sentences: &[(String, u32)]) -> (f64, u32, Vec<f64>) {
-        let mut expected: Vec<f64> = vec![0.0; model.len()];
-        let mut objs: f64 = 0.0;
-        let mut ntokens: u32 = 0;
-
+    /// Runs the E step: runs forward-backward over every sentence's lattice
+    /// on the current `model` to accumulate the expected count of each piece
+    /// and the training objective, and counts the tokens of each sentence's
+    /// Viterbi segmentation for the total token count.
+    ///
+    /// Each sentence is independent, so this is a map-reduce: every sentence
+    /// produces its own `expected` vector plus partial objective/ntokens,
+    /// and the per-sentence results are reduced by summing elementwise.
+    /// Parallelism is gated behind `self.thread_pool` (built once from
+    /// `self.n_threads`, defaulting to `None`/`1` so that training stays
+    /// deterministic and single-threaded in tests) instead of spinning up a
+    /// fresh rayon thread pool on every call.
+    fn run_e_step(&self, model: &Unigram, sentences: &[(String, u32)]) -> (f64, u32, Vec<f64>) {
         let all_sentence_freq: u32 = sentences.iter().map(|(_a, b)| *b).sum();
+        let expected_len = model.len();
 
         println!("{} sentences", sentences.len());
-        // TODO reparallelize this.
-        for (string, freq) in sentences {
-            // println!("String {:?} f={}", string, freq);
-            // println!("Sentence {}", i);
-            // let now = Instant::now();
+
+        let process = |(string, freq): &(String, u32)| -> (Vec<f64>, f64, u32) {
+            let mut expected = vec![0.0; expected_len];
             let mut lattice = Lattice::from(string, 0, 1, 2);
-            // println!("Lattice {:?}", now.elapsed());
             model.populate_nodes(&mut lattice);
-            // println!("Populate nodes {:?}", now.elapsed());
             let z: f64 = lattice.populate_marginal(*freq as f64, &mut expected);
-            // println!("Populate marginal {:?}", now.elapsed());
-            ntokens += lattice.viterbi().len() as u32;
-            // println!("Viterbi {:?}", now.elapsed());
-            // let mut max = f64::MIN;
-            // for score in &expected {
-            //     if score > &max {
-            //         max = *score;
-            //     }
-            // }
-            // println!("Expected max {:?}", max);
             if z.is_nan() {
                 panic!("likelihood is NAN. Input sentence may be too long.");
             }
+            let ntokens = lattice.viterbi().len() as u32;
+            (expected, -z / (all_sentence_freq as f64), ntokens)
+        };
+        let combine = |mut a: (Vec<f64>, f64, u32), b: (Vec<f64>, f64, u32)| {
+            for (x, y) in a.0.iter_mut().zip(b.0) {
+                *x += y;
+            }
+            (a.0, a.1 + b.1, a.2 + b.2)
+        };
+        let identity = || (vec![0.0; expected_len], 0.0, 0u32);
 
-            objs -= z / (all_sentence_freq as f64);
-            // println!("objs {:?}", now.elapsed());
-        }
+        let (expected, objs, ntokens) = if let Some(pool) = &self.thread_pool {
+            pool.install(|| sentences.par_iter().map(process).reduce(identity, combine))
+        } else {
+            sentences.iter().map(process).fold(identity(), combine)
+        };
 
         println!("Obj={} ntokens={}", objs, ntokens);
 
@@ -331,16 +801,42 @@ impl UnigramTrainer {
             new_pieces.push((piece.clone(), *freq));
             sum += freq;
         }
-        // // Here we do not use the original EM, but use the
-        // // Bayesianified/DPified EM algorithm.
-        // // https://cs.stanford.edu/~pliang/papers/tutorial-acl2007-talk.pdf
-        // // This modification will act as a sparse prior.
-        let logsum = digamma(sum);
-        let new_pieces: Vec<_> = new_pieces
-            .into_iter()
-            .map(|(s, c)| (s, digamma(c) - logsum))
-            .collect();
-        new_pieces
+
+        match self.training_mode {
+            TrainingMode::EM => {
+                // Here we do not use the original EM, but use the
+                // Bayesianified/DPified EM algorithm.
+                // https://cs.stanford.edu/~pliang/papers/tutorial-acl2007-talk.pdf
+                // This modification will act as a sparse prior.
+                let logsum = digamma(sum);
+                new_pieces
+                    .into_iter()
+                    .map(|(s, c)| (s, digamma(c) - logsum))
+                    .collect()
+            }
+            TrainingMode::PitmanYor {
+                discount,
+                concentration,
+            } => {
+                // Pitman-Yor process prior: each piece's mass is discounted
+                // by `discount` per "table" it occupies in the Chinese
+                // restaurant seating analogy, with the freed mass
+                // redistributed over the uniform base measure. This yields
+                // better-calibrated probabilities for rare, long-tail
+                // pieces than the EM sparse prior above.
+                let base = 1.0 / new_pieces.len().max(1) as f64;
+                new_pieces
+                    .into_iter()
+                    .map(|(s, freq)| {
+                        let num_tables = concentration * (1.0 + freq / concentration).ln();
+                        let score = ((freq - discount * num_tables).max(0.0)
+                            + (concentration + discount * num_tables) * base)
+                            .ln();
+                        (s, score)
+                    })
+                    .collect()
+            }
+        }
     }
     pub fn _train(
         &self,
@@ -354,9 +850,9 @@ impl UnigramTrainer {
         let mut pieces: Vec<SentencePiece> =
             Vec::with_capacity(self.vocab_size.try_into().unwrap());
         // XXX: Make sure bos, eos and unk exists and are ids 0, 1, 2
-        pieces.push(("<bos>".to_string(), 0.0));
-        pieces.push(("<eos>".to_string(), 0.0));
-        pieces.push(("<unk>".to_string(), 0.0));
+        pieces.push((self.bos_token.clone(), 0.0));
+        pieces.push((self.eos_token.clone(), 0.0));
+        pieces.push((self.unk_token.clone(), 0.0));
         pieces.extend(self.make_seed_sentence_pieces(&word_counts)?);
 
         println!("Using {} pieces for EM training", pieces.len());
@@ -368,18 +864,18 @@ impl UnigramTrainer {
             desired_vocab_size
         );
 
-        let required_chars = self.required_chars(&word_counts);
+        let required_chars = self.required_chars(&count_chars(&word_counts));
         // TODO make the model correctly ?
         let mut model = Unigram::from(&pieces, 0, 1, 2);
 
         let sentences: Vec<_> = word_counts.into_iter().collect();
 
-        loop {
+        for _ in 0..self.n_iterations {
             // Sub-EM iteration.
             for iter in 0..self.n_sub_iterations {
                 println!("-------------loop {}", iter);
                 // Executes E step
-                let (objective, num_tokens, expected) = self.run_e_step(&mut model, &sentences);
+                let (objective, num_tokens, expected) = self.run_e_step(&model, &sentences);
                 println!("E step expected={}", expected.len());
 
                 // // Executes M step.
@@ -403,7 +899,8 @@ impl UnigramTrainer {
             }
 
             // Prunes pieces.
-            self.prune_sentence_pieces();
+            pieces = self.prune_sentence_pieces(&model, &pieces, &sentences, desired_vocab_size);
+            model = Unigram::from(&pieces, 0, 1, 2);
         }
 
         // // Finally, adjusts the size of sentencepices to be |vocab_size|.
@@ -454,13 +951,16 @@ mod tests {
         word_count.insert("This is a".to_string(), 1);
         word_count.insert("こんにちは友達".to_string(), 1);
 
-        let required_chars = trainer.required_chars(&word_count);
+        let required_chars = trainer.required_chars(&count_chars(&word_count));
         assert_eq!(required_chars.len(), 13);
 
         let table = trainer.make_seed_sentence_pieces(&word_count).unwrap();
 
+        // "is " and "s " are no longer admitted as candidate sentencepieces:
+        // with `split_by_whitespace` on by default, a space may only open a
+        // piece, never sit in the middle of one.
         let target_strings = vec![
-            "s", "i", " ", "達", "友", "ん", "は", "に", "ち", "こ", "h", "a", "T", "is ", "s ",
+            "s", "i", " ", "達", "友", "ん", "は", "に", "ち", "こ", "h", "a", "T",
         ];
 
         let strings: Vec<_> = table.iter().map(|(string, _)| string).collect();
@@ -468,21 +968,19 @@ mod tests {
 
         let scores: Vec<_> = table.iter().map(|(_, score)| score).collect();
         let target_scores = vec![
-            -2.5649493574615367, // 2.0
-            -2.5649493574615367, // 2.0
-            -2.5649493574615367, // 2.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -3.258096538021482,  // 1.0
-            -1.4663370687934272, // 6.0
-            -1.8718021769015916, // 4.0
+            -2.0794415416798357, // 2.0
+            -2.0794415416798357, // 2.0
+            -2.0794415416798357, // 2.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
+            -2.772588722239781,  // 1.0
         ];
         println!("Scores {:?}", scores);
 
@@ -491,6 +989,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_prune_sentence_pieces_keeps_single_chars() {
+        let trainer = UnigramTrainerBuilder::default()
+            .with_progress(false)
+            .shrinking_factor(0.5)
+            .build();
+
+        let pieces = vec![
+            ("a".to_string(), -1.0),
+            ("b".to_string(), -1.0),
+            ("c".to_string(), -1.0),
+            ("d".to_string(), -1.0),
+            ("e".to_string(), -1.0),
+        ];
+        let model = Unigram::from(&pieces, 0, 1, 2);
+        let sentences: Vec<(String, u32)> = pieces
+            .iter()
+            .map(|(token, _)| (token.clone(), 1))
+            .collect();
+
+        // Every piece here is a single character, so `prune_sentence_pieces`
+        // must keep all of them regardless of `desired_vocab_size`: the loss
+        // ranking only ever applies to the non-required remainder.
+        let kept = trainer.prune_sentence_pieces(&model, &pieces, &sentences, 2);
+        assert_eq!(kept.len(), pieces.len());
+    }
+
     // #[test]
     // fn test_train_from_file2() {
     //     let trainer = UnigramTrainerBuilder::default()
@@ -517,4 +1042,49 @@ mod tests {
         // ln(2) - ln(3)
         assert_approx_eq!(scores[1], -0.405, 0.01);
     }
+
+    #[test]
+    fn test_required_chars_respects_coverage_cutoff() {
+        let trainer = UnigramTrainerBuilder::default()
+            .with_progress(false)
+            .character_coverage(0.9)
+            .build();
+
+        let mut all_chars: HashMap<char, u32> = HashMap::new();
+        all_chars.insert('a', 10);
+        all_chars.insert('b', 5);
+        all_chars.insert('c', 1);
+
+        // total=16, target=ceil(16*0.9)=15: 'a'+'b' already cover 15
+        // occurrences, so the single occurrence of 'c' falls outside the
+        // coverage budget and is left out.
+        let required_chars = trainer.required_chars(&all_chars);
+        assert_eq!(
+            required_chars,
+            vec!["a".to_string(), "b".to_string()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_run_m_step_pitman_yor() {
+        let trainer = UnigramTrainerBuilder::default()
+            .with_progress(false)
+            .training_mode(TrainingMode::PitmanYor {
+                discount: 0.5,
+                concentration: 1.0,
+            })
+            .build();
+
+        let pieces = vec![("a".to_string(), 0.0), ("b".to_string(), 0.0)];
+        let expected = vec![3.0, 1.0];
+
+        let new_pieces = trainer.run_m_step(&pieces, &expected);
+        let scores: Vec<_> = new_pieces.iter().map(|(_, score)| *score).collect();
+
+        // base = 1 / 2 pieces = 0.5; num_tables = concentration * ln(1 + freq / concentration).
+        assert_approx_eq!(scores[0], 1.1485, 0.001);
+        assert_approx_eq!(scores[1], 0.2827, 0.001);
+    }
 }
\ No newline at end of file